@@ -1,8 +1,9 @@
-use chrono::{NaiveDate, NaiveTime};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
 use nom::{
     character::complete::{char, one_of},
     combinator::opt,
     number::complete::float,
+    sequence::{preceded, separated_pair},
     IResult,
 };
 
@@ -19,6 +20,17 @@ pub enum RmcStatusOfFix {
     Invalid,
 }
 
+/// FAA mode indicator, field 12 of the RMC sentence (NMEA 2.3 and later).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RmcFaaMode {
+    Autonomous,
+    Differential,
+    Estimated,
+    NotValid,
+    Simulator,
+    Manual,
+}
+
 /// RMC - Recommended Minimum Navigation Information
 ///
 /// <https://gpsd.gitlab.io/gpsd/NMEA.html#_bwc_bearing_distance_to_waypoint_great_circle>
@@ -52,6 +64,193 @@ pub struct RmcData {
     pub lon: Option<f64>,
     pub speed_over_ground: Option<f32>,
     pub true_course: Option<f32>,
+    /// Magnetic variation in degrees, West negative, East positive.
+    pub magnetic_variation: Option<f32>,
+    /// FAA mode indicator (NMEA 2.3 and later). `None` for SiRF chipsets,
+    /// which omit the field entirely.
+    pub faa_mode: Option<RmcFaaMode>,
+}
+
+/// Seconds in a GPS week, used to derive the week number / time-of-week.
+const GPS_WEEK_SECONDS: f64 = 604_800.0;
+
+/// Conversion factor from knots to meters per second.
+const KNOTS_TO_MPS: f32 = 0.514444;
+
+impl RmcData {
+    /// Magnetic course: `true_course` corrected by `magnetic_variation`,
+    /// normalized to `[0, 360)`.
+    pub fn magnetic_course(&self) -> Option<f32> {
+        let true_course = self.true_course?;
+        let magnetic_variation = self.magnetic_variation?;
+        Some((true_course - magnetic_variation).rem_euclid(360.0))
+    }
+
+    /// GPS week number (rolled over modulo 1024) and time-of-week in
+    /// seconds, derived from `fix_date` and `fix_time`. `leap_seconds` is
+    /// the UTC-to-GPS leap second offset (18 as of 2017-01-01). Returns
+    /// `None` if either `fix_date` or `fix_time` is absent.
+    pub fn gps_time(&self, leap_seconds: u8) -> Option<(u16, f64)> {
+        let (week, tow) = self.gps_time_continuous(leap_seconds)?;
+        Some(((week % 1024) as u16, tow))
+    }
+
+    /// Like [`Self::gps_time`], but without the 1024/8192 rollover modulo.
+    pub fn gps_time_continuous(&self, leap_seconds: u8) -> Option<(u32, f64)> {
+        let fix_date = self.fix_date?;
+        let fix_time = self.fix_time?;
+        let gps_epoch = NaiveDate::from_ymd(1980, 1, 6).and_hms(0, 0, 0);
+        let fix = NaiveDateTime::new(fix_date, fix_time);
+        let elapsed = fix.signed_duration_since(gps_epoch);
+        let total_seconds = elapsed.num_milliseconds() as f64 / 1000.0 + leap_seconds as f64;
+        let week = (total_seconds / GPS_WEEK_SECONDS).floor();
+        let tow = total_seconds - week * GPS_WEEK_SECONDS;
+        Some((week as u32, tow))
+    }
+
+    /// North/East/Down velocity in meters per second, derived from
+    /// `speed_over_ground` and `true_course`. `down` is always `0.0`, since
+    /// RMC carries no vertical rate. Returns `None` unless speed, course,
+    /// and a non-[`RmcStatusOfFix::Invalid`] fix are present.
+    pub fn velocity_ned(&self) -> Option<(f32, f32, f32)> {
+        if self.status_of_fix == RmcStatusOfFix::Invalid {
+            return None;
+        }
+        let speed_over_ground = self.speed_over_ground?;
+        let true_course = self.true_course?;
+        let v = speed_over_ground * KNOTS_TO_MPS;
+        let north = v * true_course.to_radians().cos();
+        let east = v * true_course.to_radians().sin();
+        Some((north, east, 0.0))
+    }
+
+    /// Formats this data back into a `$--RMC,...*hh` sentence, ending in
+    /// `\r\n`, with `talker` (e.g. `"GP"`) as the talker ID.
+    pub fn to_sentence(&self, talker: &str) -> String {
+        let (lat, lat_hem) = format_lat(self.lat);
+        let (lon, lon_hem) = format_lon(self.lon);
+        let (magnetic_variation, magnetic_variation_hem) =
+            format_magnetic_variation(self.magnetic_variation);
+        let fields = [
+            format_hms(self.fix_time),
+            format_status_of_fix(self.status_of_fix).to_string(),
+            lat,
+            lat_hem,
+            lon,
+            lon_hem,
+            self.speed_over_ground
+                .map_or(String::new(), |v| v.to_string()),
+            self.true_course.map_or(String::new(), |v| v.to_string()),
+            format_date(self.fix_date),
+            magnetic_variation,
+            magnetic_variation_hem,
+            self.faa_mode.map_or(String::new(), format_faa_mode),
+        ];
+        let payload = format!("{}RMC,{}", talker, fields.join(","));
+        let checksum = payload.bytes().fold(0u8, |acc, b| acc ^ b);
+        format!("${}*{:02X}\r\n", payload, checksum)
+    }
+}
+
+fn format_hms(time: Option<NaiveTime>) -> String {
+    match time {
+        Some(time) => format!(
+            "{:02}{:02}{:02}.{:02}",
+            time.hour(),
+            time.minute(),
+            time.second(),
+            time.nanosecond() / 10_000_000
+        ),
+        None => String::new(),
+    }
+}
+
+fn format_date(date: Option<NaiveDate>) -> String {
+    match date {
+        Some(date) => format!(
+            "{:02}{:02}{:02}",
+            date.day(),
+            date.month(),
+            date.year() % 100
+        ),
+        None => String::new(),
+    }
+}
+
+fn format_status_of_fix(status_of_fix: RmcStatusOfFix) -> char {
+    match status_of_fix {
+        RmcStatusOfFix::Autonomous => 'A',
+        RmcStatusOfFix::Differential => 'D',
+        RmcStatusOfFix::Invalid => 'V',
+    }
+}
+
+fn format_faa_mode(faa_mode: RmcFaaMode) -> String {
+    match faa_mode {
+        RmcFaaMode::Autonomous => "A",
+        RmcFaaMode::Differential => "D",
+        RmcFaaMode::Estimated => "E",
+        RmcFaaMode::NotValid => "N",
+        RmcFaaMode::Simulator => "S",
+        RmcFaaMode::Manual => "M",
+    }
+    .to_string()
+}
+
+/// Formats signed degrees (West negative / South negative) into the
+/// `ddmm.mmmm` / `dddmm.mmmm` value and its `N`/`S`/`E`/`W` hemisphere
+/// letter, as used by `lat` (2-digit degrees) and `lon` (3-digit degrees).
+fn format_lat_lon(
+    value: f64,
+    degrees_width: usize,
+    positive: char,
+    negative: char,
+) -> (String, String) {
+    let hemisphere = if value.is_sign_negative() {
+        negative
+    } else {
+        positive
+    };
+    let value = value.abs();
+    let degrees = value.trunc();
+    let minutes = (value - degrees) * 60.0;
+    (
+        format!(
+            "{:0width$}{:07.4}",
+            degrees as u32,
+            minutes,
+            width = degrees_width
+        ),
+        hemisphere.to_string(),
+    )
+}
+
+fn format_lat(lat: Option<f64>) -> (String, String) {
+    match lat {
+        Some(lat) => format_lat_lon(lat, 2, 'N', 'S'),
+        None => (String::new(), String::new()),
+    }
+}
+
+fn format_lon(lon: Option<f64>) -> (String, String) {
+    match lon {
+        Some(lon) => format_lat_lon(lon, 3, 'E', 'W'),
+        None => (String::new(), String::new()),
+    }
+}
+
+fn format_magnetic_variation(magnetic_variation: Option<f32>) -> (String, String) {
+    match magnetic_variation {
+        Some(magnetic_variation) => {
+            let hemisphere = if magnetic_variation.is_sign_negative() {
+                'W'
+            } else {
+                'E'
+            };
+            (magnetic_variation.abs().to_string(), hemisphere.to_string())
+        }
+        None => (String::new(), String::new()),
+    }
 }
 
 fn do_parse_rmc(i: &str) -> IResult<&str, RmcData> {
@@ -72,7 +271,30 @@ fn do_parse_rmc(i: &str) -> IResult<&str, RmcData> {
     let (i, true_course) = opt(float)(i)?;
     let (i, _) = char(',')(i)?;
     let (i, fix_date) = opt(parse_date)(i)?;
-    let (i, _) = char(',')(i)?;
+    // Older, pre-NMEA-2.3 (SiRF-style) sentences stop right after `fix_date`,
+    // omitting the magnetic-variation and FAA-mode fields entirely rather
+    // than leaving them as empty comma-separated fields, so both trailing
+    // groups below must be optional as a whole, not just their values.
+    let (i, magnetic_variation_fields) = opt(preceded(
+        char(','),
+        separated_pair(opt(float), char(','), opt(one_of("EW"))),
+    ))(i)?;
+    let (magnetic_variation, magnetic_variation_direction) =
+        magnetic_variation_fields.unwrap_or((None, None));
+    let magnetic_variation = magnetic_variation.map(|v| match magnetic_variation_direction {
+        Some('W') => -v,
+        _ => v,
+    });
+    let (i, faa_mode) = opt(preceded(char(','), opt(one_of("ADENSM"))))(i)?;
+    let faa_mode = faa_mode.flatten().map(|c| match c {
+        'A' => RmcFaaMode::Autonomous,
+        'D' => RmcFaaMode::Differential,
+        'E' => RmcFaaMode::Estimated,
+        'N' => RmcFaaMode::NotValid,
+        'S' => RmcFaaMode::Simulator,
+        'M' => RmcFaaMode::Manual,
+        _ => unreachable!(),
+    });
     Ok((
         i,
         RmcData {
@@ -83,6 +305,8 @@ fn do_parse_rmc(i: &str) -> IResult<&str, RmcData> {
             lon: lat_lon.map(|v| v.1),
             speed_over_ground,
             true_course,
+            magnetic_variation,
+            faa_mode,
         },
     ))
 }
@@ -156,6 +380,9 @@ mod tests {
 
         assert_relative_eq!(rmc_data.speed_over_ground.unwrap(), 0.5);
         assert_relative_eq!(rmc_data.true_course.unwrap(), 54.7);
+        assert_relative_eq!(rmc_data.magnetic_variation.unwrap(), 20.3);
+        assert_relative_eq!(rmc_data.magnetic_course().unwrap(), 54.7 - 20.3);
+        assert_eq!(rmc_data.faa_mode.unwrap(), RmcFaaMode::Autonomous);
 
         let s = parse_nmea_sentence("$GPRMC,,V,,,,,,,,,,N*53").unwrap();
         let rmc = parse_rmc(s).unwrap();
@@ -168,8 +395,145 @@ mod tests {
                 lon: None,
                 speed_over_ground: None,
                 true_course: None,
+                magnetic_variation: None,
+                faa_mode: Some(RmcFaaMode::NotValid),
             },
             rmc
         );
     }
+
+    #[test]
+    fn test_parse_rmc_pre_nmea23_sirf() {
+        // Pre-NMEA-2.3 SiRF chipsets stop right after the date, omitting the
+        // magnetic-variation and FAA-mode fields (and their commas) entirely.
+        let s =
+            parse_nmea_sentence("$GPRMC,225446.33,A,4916.45,N,12311.12,W,000.5,054.7,191194*2C")
+                .unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+        assert_eq!(
+            rmc_data.fix_date.unwrap(),
+            NaiveDate::from_ymd(1994, 11, 19)
+        );
+        assert_eq!(rmc_data.magnetic_variation, None);
+        assert_eq!(rmc_data.faa_mode, None);
+    }
+
+    #[test]
+    fn test_parse_rmc_nmea23_without_faa_mode() {
+        // NMEA 2.3 sentences that carry magnetic variation but stop before
+        // the FAA-mode field (field 12) must still parse, with `faa_mode`
+        // coming back as `None` rather than erroring.
+        let s = parse_nmea_sentence(
+            "$GPRMC,225446.33,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*46",
+        )
+        .unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+        assert_relative_eq!(rmc_data.magnetic_variation.unwrap(), 20.3);
+        assert_eq!(rmc_data.faa_mode, None);
+    }
+
+    #[test]
+    fn test_gps_time() {
+        let s = parse_nmea_sentence(
+            "$GPRMC,225446.33,A,4916.45,N,12311.12,W,\
+                                  000.5,054.7,191194,020.3,E,A*2B",
+        )
+        .unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+
+        let (week, tow) = rmc_data.gps_time(18).unwrap();
+        assert_eq!(week, 775);
+        assert_relative_eq!(tow, 600_904.33, epsilon = 0.01);
+
+        let (continuous_week, continuous_tow) = rmc_data.gps_time_continuous(18).unwrap();
+        assert_eq!(continuous_week, 775);
+        assert_relative_eq!(continuous_tow, tow, epsilon = 0.01);
+
+        let s = parse_nmea_sentence("$GPRMC,,V,,,,,,,,,,N*53").unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+        assert_eq!(rmc_data.gps_time(18), None);
+    }
+
+    #[test]
+    fn test_gps_time_week_rollover() {
+        // Far enough past the GPS epoch that the continuous week number
+        // exceeds 1024, so `gps_time` (rolled over) and `gps_time_continuous`
+        // must actually differ.
+        let rmc_data = RmcData {
+            fix_time: Some(NaiveTime::from_hms(0, 0, 0)),
+            fix_date: Some(NaiveDate::from_ymd(2020, 1, 1)),
+            status_of_fix: RmcStatusOfFix::Autonomous,
+            lat: None,
+            lon: None,
+            speed_over_ground: None,
+            true_course: None,
+            magnetic_variation: None,
+            faa_mode: None,
+        };
+
+        let (continuous_week, continuous_tow) = rmc_data.gps_time_continuous(18).unwrap();
+        assert_eq!(continuous_week, 2086);
+        assert_relative_eq!(continuous_tow, 259_218.0, epsilon = 0.01);
+
+        let (week, tow) = rmc_data.gps_time(18).unwrap();
+        assert_eq!(week, 38);
+        assert_relative_eq!(tow, continuous_tow, epsilon = 0.01);
+    }
+
+    #[test]
+    fn test_velocity_ned() {
+        let s = parse_nmea_sentence(
+            "$GPRMC,225446.33,A,4916.45,N,12311.12,W,\
+                                  000.5,054.7,191194,020.3,E,A*2B",
+        )
+        .unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+
+        let (north, east, down) = rmc_data.velocity_ned().unwrap();
+        assert_relative_eq!(north, 0.1486377, epsilon = 1e-6);
+        assert_relative_eq!(east, 0.2099285, epsilon = 1e-6);
+        assert_relative_eq!(down, 0.0);
+
+        let s = parse_nmea_sentence("$GPRMC,,V,,,,,,,,,,N*53").unwrap();
+        let rmc_data = parse_rmc(s).unwrap();
+        assert_eq!(rmc_data.velocity_ned(), None);
+    }
+
+    fn assert_round_trips(data: &str) {
+        let rmc_data = parse_rmc(parse_nmea_sentence(data).unwrap()).unwrap();
+        let sentence = rmc_data.to_sentence("GP");
+        let round_tripped = parse_rmc(parse_nmea_sentence(&sentence).unwrap()).unwrap();
+
+        assert_eq!(round_tripped.fix_time, rmc_data.fix_time);
+        assert_eq!(round_tripped.fix_date, rmc_data.fix_date);
+        assert_eq!(round_tripped.status_of_fix, rmc_data.status_of_fix);
+        assert_eq!(round_tripped.faa_mode, rmc_data.faa_mode);
+        match (round_tripped.lat, rmc_data.lat) {
+            (Some(a), Some(b)) => assert_relative_eq!(a, b, epsilon = 1e-4),
+            (a, b) => assert_eq!(a, b),
+        }
+        match (round_tripped.lon, rmc_data.lon) {
+            (Some(a), Some(b)) => assert_relative_eq!(a, b, epsilon = 1e-4),
+            (a, b) => assert_eq!(a, b),
+        }
+        assert_eq!(round_tripped.speed_over_ground, rmc_data.speed_over_ground);
+        assert_eq!(round_tripped.true_course, rmc_data.true_course);
+        assert_eq!(
+            round_tripped.magnetic_variation,
+            rmc_data.magnetic_variation
+        );
+    }
+
+    #[test]
+    fn test_to_sentence_round_trip() {
+        assert_round_trips(
+            "$GPRMC,225446.33,A,4916.45,N,12311.12,W,\
+                                  000.5,054.7,191194,020.3,E,A*2B",
+        );
+        assert_round_trips("$GPRMC,,V,,,,,,,,,,N*53");
+        assert_round_trips(
+            "$GPRMC,225446.33,A,4916.45,N,12311.12,W,\
+                                  000.5,054.7,191194,020.3,W,A*39",
+        );
+    }
 }